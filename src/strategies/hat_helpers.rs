@@ -1,13 +1,27 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 use game::*;
 use helpers::*;
 
+/// The type used for moduli and values throughout the hat-guessing convention machinery. Kept
+/// behind an alias so a future big-integer backend (for conventions that want to pack
+/// substantially more than 64 bits of hat information per move) can be dropped in without
+/// touching every signature again.
+pub type Modulus = u64;
+
+/// Panics (in debug builds) if `a * b` would overflow `Modulus`.
+fn debug_assert_no_overflow_mul(a: Modulus, b: Modulus) {
+    debug_assert!(a.checked_mul(b).is_some(), "Modulus multiplication overflowed: {} * {}", a, b);
+}
+
 #[derive(Debug,Clone)]
 pub struct ModulusInformation {
-    pub modulus: u32,
-    pub value: u32,
+    pub modulus: Modulus,
+    pub value: Modulus,
 }
 impl ModulusInformation {
-    pub fn new(modulus: u32, value: u32) -> Self {
+    pub fn new(modulus: Modulus, value: Modulus) -> Self {
         assert!(value < modulus);
         ModulusInformation {
             modulus: modulus,
@@ -19,14 +33,16 @@ impl ModulusInformation {
         Self::new(1, 0)
     }
 
-    pub fn combine(&mut self, other: Self, max_modulus: u32) {
+    pub fn combine(&mut self, other: Self, max_modulus: Modulus) {
         assert!(other.modulus <= self.info_remaining(max_modulus));
+        debug_assert_no_overflow_mul(self.modulus, other.value);
         self.value = self.value + self.modulus * other.value;
+        debug_assert_no_overflow_mul(self.modulus, other.modulus);
         self.modulus = std::cmp::min(max_modulus, self.modulus * other.modulus);
         assert!(self.value < self.modulus);
     }
 
-    pub fn info_remaining(&self, max_modulus: u32) -> u32 {
+    pub fn info_remaining(&self, max_modulus: Modulus) -> Modulus {
         // We want to find the largest number `result` such that
         // `self.combine(other, max_modulus)` works whenever `other.modulus == result`.
         // `other.value` can be up to `result - 1`, so calling combine could increase our value to
@@ -35,12 +51,14 @@ impl ModulusInformation {
         // Therefore, we compute the largest number `result` such that
         // `self.value + self.modulus * (result - 1) < max_modulus`.
         let result = (max_modulus - self.value - 1) / self.modulus + 1;
+        debug_assert_no_overflow_mul(self.modulus, result - 1);
         assert!(self.value + self.modulus * (result - 1) < max_modulus);
+        debug_assert_no_overflow_mul(self.modulus, (result + 1) - 1);
         assert!(self.value + self.modulus * ((result + 1) - 1) >= max_modulus);
         result
     }
 
-    pub fn split(&mut self, modulus: u32) -> Self {
+    pub fn split(&mut self, modulus: Modulus) -> Self {
         assert!(self.modulus >= modulus);
         let original_modulus = self.modulus;
         let original_value = self.value;
@@ -50,16 +68,17 @@ impl ModulusInformation {
         // `value + (self.modulus - 1) * modulus < original_modulus`.
         // TODO: find an explanation of why this makes everything work out
         self.modulus = (original_modulus - value - 1) / modulus + 1;
+        debug_assert_no_overflow_mul(modulus, self.value);
         assert!(original_value == value + modulus * self.value);
         Self::new(modulus, value)
     }
 
-    pub fn cast_up(&mut self, modulus: u32) {
+    pub fn cast_up(&mut self, modulus: Modulus) {
         assert!(self.modulus <= modulus);
         self.modulus = modulus;
     }
 
-    // pub fn cast_down(&mut self, modulus: u32) {
+    // pub fn cast_down(&mut self, modulus: Modulus) {
     //     assert!(self.modulus >= modulus);
     //     assert!(self.value < modulus);
     //     self.modulus = modulus;
@@ -78,12 +97,12 @@ impl ModulusInformation {
 
 pub trait Question {
     // how much info does this question ask for?
-    fn info_amount(&self) -> u32;
+    fn info_amount(&self) -> Modulus;
     // get the answer to this question, given cards
-    fn answer(&self, &Cards, &BoardState) -> u32;
+    fn answer(&self, &Cards, &BoardState) -> Modulus;
     // process the answer to this question, updating card info
     fn acknowledge_answer(
-        &self, value: u32, &mut HandInfo<CardPossibilityTable>, &BoardState
+        &self, value: Modulus, &mut HandInfo<CardPossibilityTable>, &BoardState
     );
 
     fn answer_info(&self, hand: &Cards, board: &BoardState) -> ModulusInformation {
@@ -102,6 +121,52 @@ pub trait Question {
         assert!(self.info_amount() == answer.modulus);
         self.acknowledge_answer(answer.value, hand_info, board);
     }
+
+    /// The probability of each possible answer value (indices `0..self.info_amount()`), given
+    /// everything common knowledge lets us infer about the hand being asked about. Questions
+    /// whose answer is nearly certain should report a spiked distribution here so that
+    /// `select_greedy_question` can avoid wasting `total_info` on them; the default assumes
+    /// nothing is known and every answer is equally likely.
+    fn answer_distribution(&self, _hand_info: &HandInfo<CardPossibilityTable>, _board: &BoardState) -> Vec<f32> {
+        vec![1.0 / (self.info_amount() as f32); self.info_amount() as usize]
+    }
+}
+
+/// The Shannon entropy of `distribution`, in bits, ignoring (treating as contributing 0) any
+/// zero-probability entries.
+fn entropy(distribution: &[f32]) -> f32 {
+    -distribution.iter().filter(|p| **p > 0.0).map(|p| p * p.log2()).sum::<f32>()
+}
+
+/// Greedily picks, from `candidates`, the question whose answer is closest to uniformly
+/// distributed per bit of `info_amount()` it consumes - i.e. the question that extracts the most
+/// expected information per unit of our scarce `info_remaining` budget - among those that still
+/// fit. Candidates with an `info_amount()` of 1 are never picked. Returns `None` if no candidate
+/// with an `info_amount()` above 1 fits within `info_remaining`.
+///
+/// Candidates whose score isn't comparable (e.g. a buggy `answer_distribution` override
+/// producing `NaN`) are treated as tied with whatever we're comparing them against, rather than
+/// panicking mid-game.
+pub fn select_greedy_question(
+    candidates: Vec<Box<Question>>,
+    hand_info: &HandInfo<CardPossibilityTable>,
+    board: &BoardState,
+    info_remaining: Modulus,
+) -> Option<Box<Question>> {
+    candidates.into_iter()
+        .filter(|question| question.info_amount() <= info_remaining && question.info_amount() > 1)
+        .max_by(|a, b| {
+            let score_a = entropy(&a.answer_distribution(hand_info, board)) / (a.info_amount() as f32).log2();
+            let score_b = entropy(&b.answer_distribution(hand_info, board)) / (b.info_amount() as f32).log2();
+            score_a.partial_cmp(&score_b).unwrap_or(std::cmp::Ordering::Equal)
+        })
+}
+
+/// A canonical, round-trippable snapshot of a `PublicInformation`'s common-knowledge state, for
+/// debugging convention divergence and for regression tests driven by `replay_and_find_divergence`.
+#[derive(Debug, Clone)]
+pub struct PublicInformationSnapshot {
+    pub hand_infos: Vec<(Player, HandInfo<CardPossibilityTable>)>,
 }
 
 pub trait PublicInformation: Clone {
@@ -117,6 +182,28 @@ pub trait PublicInformation: Clone {
 
     fn agrees_with(&self, other: Self) -> bool;
 
+    /// A canonical, round-trippable snapshot of everything we track: every player's
+    /// `HandInfo<CardPossibilityTable>`, taken through the same `get_player_info` interface the
+    /// rest of this module uses. `replay_and_find_divergence` takes one of these (via
+    /// `from_snapshot`) whenever it needs to resume a replay mid-game instead of from scratch, and
+    /// builds one (via this method) for each side of a detected divergence so the mismatch can be
+    /// diffed directly.
+    fn to_snapshot(&self, players: &[Player]) -> PublicInformationSnapshot {
+        PublicInformationSnapshot {
+            hand_infos: players.iter().map(|player| (player.clone(), self.get_player_info(player))).collect(),
+        }
+    }
+
+    /// Rebuilds a `Self` from a snapshot taken by `to_snapshot`, starting from a fresh `new(board)`
+    /// and replaying the stored hand infos through `set_player_infos`. Used by
+    /// `replay_and_find_divergence` to resume a replay from a recorded mid-game snapshot instead of
+    /// from the start of the game.
+    fn from_snapshot(board: &BoardState, snapshot: &PublicInformationSnapshot) -> Self {
+        let mut info = Self::new(board);
+        info.set_player_infos(snapshot.hand_infos.clone());
+        info
+    }
+
     /// By defining `ask_questions`, we decides which `Question`s a player learns the answers to.
     ///
     /// A player "asks" a question by calling the callback. Questions can depend on the answers to
@@ -125,10 +212,10 @@ pub trait PublicInformation: Clone {
     /// is not modified and thus reflects the state before any player "asked" any question.
     ///
     /// The product of the `info_amount()`s of all questions we have may not exceed `total_info`.
-    /// For convenience, we pass a `&mut u32` to the callback, and it will be updated to the
+    /// For convenience, we pass a `&mut Modulus` to the callback, and it will be updated to the
     /// "remaining" information amount.
-    fn ask_questions<Callback>(&self, &Player, &mut HandInfo<CardPossibilityTable>, Callback, total_info: u32)
-        where Callback: FnMut(&mut HandInfo<CardPossibilityTable>, &mut u32, Box<Question>);
+    fn ask_questions<Callback>(&self, &Player, &mut HandInfo<CardPossibilityTable>, Callback, total_info: Modulus)
+        where Callback: FnMut(&mut HandInfo<CardPossibilityTable>, &mut Modulus, Box<Question>);
 
     fn set_player_infos(&mut self, infos: Vec<(Player, HandInfo<CardPossibilityTable>)>) {
         for (player, new_hand_info) in infos {
@@ -138,12 +225,12 @@ pub trait PublicInformation: Clone {
     }
 
     fn get_hat_info_for_player(
-        &self, player: &Player, hand_info: &mut HandInfo<CardPossibilityTable>, total_info: u32, view: &OwnedGameView
+        &self, player: &Player, hand_info: &mut HandInfo<CardPossibilityTable>, total_info: Modulus, view: &OwnedGameView
     ) -> ModulusInformation {
         assert!(player != &view.player);
         let mut answer_info = ModulusInformation::none();
         {
-            let callback = |hand_info: &mut HandInfo<CardPossibilityTable>, info_remaining: &mut u32, question: Box<Question>| {
+            let callback = |hand_info: &mut HandInfo<CardPossibilityTable>, info_remaining: &mut Modulus, question: Box<Question>| {
                 let new_answer_info = question.answer_info(view.get_hand(player), view.get_board());
                 question.acknowledge_answer_info(new_answer_info.clone(), hand_info, view.get_board());
                 answer_info.combine(new_answer_info, total_info);
@@ -164,7 +251,7 @@ pub trait PublicInformation: Clone {
     ) {
         let total_info = info.modulus;
         {
-            let callback = |hand_info: &mut HandInfo<CardPossibilityTable>, info_remaining: &mut u32, question: Box<Question>| {
+            let callback = |hand_info: &mut HandInfo<CardPossibilityTable>, info_remaining: &mut Modulus, question: Box<Question>| {
                 let answer_info = info.split(question.info_amount());
                 question.acknowledge_answer_info(answer_info, hand_info, board);
                 *info_remaining = info.modulus;
@@ -177,7 +264,7 @@ pub trait PublicInformation: Clone {
     /// When deciding on a move, if we can choose between `total_info` choices,
     /// `self.get_hat_sum(total_info, view)` tells us which choice to take, and at the same time
     /// mutates `self` to simulate the choice becoming common knowledge.
-    fn get_hat_sum(&mut self, total_info: u32, view: &OwnedGameView) -> ModulusInformation {
+    fn get_hat_sum(&mut self, total_info: Modulus, view: &OwnedGameView) -> ModulusInformation {
         if total_info == 1 {
             return ModulusInformation::none();
         }
@@ -237,23 +324,53 @@ pub trait PublicInformation: Clone {
         info
     }
 
+    /// Derives a value that is common knowledge (every player can compute it identically from
+    /// public information alone) and that changes whenever the tracked common-knowledge state or
+    /// the turn number changes, so it can serve as a shared source of randomness; two calls on the
+    /// same state in the same turn return the same value. We hash a `to_snapshot` of every player's
+    /// `HandInfo<CardPossibilityTable>` (as tracked by `self`, i.e. before this move updates it)
+    /// together with the current turn number, so the stream only ever advances on events that are
+    /// visible to everybody and never depends on anyone's private hand knowledge. We reuse
+    /// `PublicInformationSnapshot` here rather than inventing a second serialization, since it's
+    /// already the canonical stable representation of common-knowledge state this module needs.
+    ///
+    /// The hash must come out identically no matter which player computes it, so the snapshot's
+    /// players must be listed in some order that's independent of who's asking: `view`-relative
+    /// order doesn't work, since `view.get_other_players()` lists players in an order that depends
+    /// on the viewer and `view.player` is always appended last. We instead sort by each player's
+    /// rendered hand info, falling back to the player's own rendering to break ties - a player
+    /// identity tiebreak is necessary (not just cosmetic) because `sort_by_key` is stable: if two
+    /// players' hand infos render identically (e.g. at the start of a game, before anyone knows
+    /// anything), a hand-info-only sort would leave them in whatever order they were pushed in,
+    /// which is `view`-relative and would desync the two viewers again.
+    fn common_knowledge_rng(&self, view: &OwnedGameView) -> u64 {
+        let mut players = view.get_other_players();
+        players.push(view.player);
+        players.sort_by_key(|player| {
+            (format!("{:?}", self.get_player_info(player)), format!("{:?}", player))
+        });
+        let snapshot = self.to_snapshot(&players);
+        let mut hasher = DefaultHasher::new();
+        format!("{:?}", snapshot).hash(&mut hasher);
+        view.board.turn.hash(&mut hasher);
+        hasher.finish()
+    }
+
     /// Suppose we as the current player can do some action that others don't know we can, but that
     /// others will recognize once they see it (say we discard a card that we only privately know
     /// to be dead). We can use this to transmit half a bit of information: We get a hat sum; if the
-    /// sum is 0, we do that action to transmit this hat information, if the sum is something else,
-    /// we don't (and thus don't transmit information since other players won't learn that we could
-    /// do the action).
+    /// sum equals a common-knowledge threshold drawn from `common_knowledge_rng`, we do that
+    /// action to transmit this hat information, if the sum is something else, we don't (and thus
+    /// don't transmit information since other players won't learn that we could do the action).
     ///
     /// We will have (roughly) a probability of `1/num_states` of choosing to do the action, and if
     /// we do, we transmit `log(num_states)` bits of  information to each player.
     /// Note that other players need to know how we chose `num_states`.
     // FIXME: talk about optimum!
-    // TODO: Randomization here could actually help! (For instance, right now, calling this
-    // method twice in a row is kind of useless.) We'd just have to do some careful bookkeeping of
-    // our random state.
-    fn decide_action_not_known_to_be_possible(&mut self, num_states: u32, view: &OwnedGameView) -> bool {
+    fn decide_action_not_known_to_be_possible(&mut self, num_states: Modulus, view: &OwnedGameView) -> bool {
         let hat_sum = self.clone().get_hat_sum(num_states, view);
-        if hat_sum.value == 0 {
+        let threshold = self.common_knowledge_rng(view) % num_states;
+        if hat_sum.value == threshold {
             let _ = self.get_hat_sum(num_states, view);
             true
         } else {
@@ -262,10 +379,449 @@ pub trait PublicInformation: Clone {
     }
     /// If we infer that the player making the move called `decide_action_not_known_to_be_possible()`
     /// and got the result `true`, we call `update_from_action_not_known_to_be_possible`.
-    fn update_from_action_not_known_to_be_possible(&mut self, num_states: u32, view: &OwnedGameView) {
+    fn update_from_action_not_known_to_be_possible(&mut self, num_states: Modulus, view: &OwnedGameView) {
+        let threshold = self.common_knowledge_rng(view) % num_states;
         self.update_from_hat_sum(ModulusInformation {
             modulus: num_states,
-            value: 0,
+            value: threshold,
         }, view);
     }
 }
+
+/// One recorded move's worth of data needed to replay the hat convention: the player who acted,
+/// how many states they were choosing between, and every player's `OwnedGameView` at that point
+/// in the game (including the actor's own).
+pub struct RecordedHatMove {
+    pub actor: Player,
+    pub num_states: Modulus,
+    pub views: Vec<(Player, OwnedGameView)>,
+}
+
+/// Where and how a replayed hat convention first diverged: the index into the replayed moves,
+/// which player's reconstructed state disagreed with the actor's, and both sides' snapshots so
+/// the mismatch can be diffed directly instead of being re-run and re-snapshotted by hand.
+#[derive(Debug)]
+pub struct HatConventionDivergence {
+    pub move_index: usize,
+    pub player: Player,
+    pub actor_snapshot: PublicInformationSnapshot,
+    pub player_snapshot: PublicInformationSnapshot,
+}
+
+/// Re-runs a recorded sequence of hat-convention moves against one `PublicInformation` per player -
+/// starting from `starting_snapshot` if given, or a fresh `P::new(board)` otherwise - so that a
+/// divergence found deep into a long recorded game can be re-investigated starting from a snapshot
+/// taken just before it, instead of replaying the whole game again. The actor calls `get_hat_sum`
+/// on their own state, then every other player calls `update_from_hat_sum` on theirs, mirroring
+/// what would happen during a real game. After each move, every player's resulting state is
+/// checked against the actor's with `agrees_with`.
+///
+/// This turns the otherwise-opaque `agrees_with` check into a diffable artifact: instead of
+/// discovering convention divergence somewhere deep into a game, this surfaces the exact move at
+/// which a convention's encode (`get_hat_sum`) and decode (`update_from_hat_sum`) paths disagree,
+/// along with both sides' `PublicInformationSnapshot`s so the mismatch itself is inspectable
+/// without re-running anything by hand.
+///
+/// Returns `None` if the whole replay agrees throughout.
+pub fn replay_and_find_divergence<P: PublicInformation>(
+    players: &[Player],
+    board: &BoardState,
+    moves: &[RecordedHatMove],
+    starting_snapshot: Option<&PublicInformationSnapshot>,
+) -> Option<HatConventionDivergence> {
+    let mut states: Vec<(Player, P)> = players.iter()
+        .map(|player| {
+            let state = match starting_snapshot {
+                Some(snapshot) => P::from_snapshot(board, snapshot),
+                None => P::new(board),
+            };
+            (player.clone(), state)
+        })
+        .collect();
+    for (move_index, recorded_move) in moves.iter().enumerate() {
+        let actor_view = &recorded_move.views.iter()
+            .find(|(player, _)| *player == recorded_move.actor)
+            .expect("recorded move must include the actor's own view").1;
+        let actor_state_index = states.iter()
+            .position(|(player, _)| *player == recorded_move.actor)
+            .expect("recorded move's actor must be among `players`");
+        let hat_sum = states[actor_state_index].1.get_hat_sum(recorded_move.num_states, actor_view);
+        let canonical = states[actor_state_index].1.clone();
+        for (player, state) in states.iter_mut() {
+            if *player == recorded_move.actor {
+                continue;
+            }
+            let view = &recorded_move.views.iter()
+                .find(|(p, _)| p == player)
+                .expect("recorded move must include every player's view").1;
+            state.update_from_hat_sum(hat_sum.clone(), view);
+            if !state.agrees_with(canonical.clone()) {
+                return Some(HatConventionDivergence {
+                    move_index,
+                    player: player.clone(),
+                    actor_snapshot: canonical.to_snapshot(players),
+                    player_snapshot: state.to_snapshot(players),
+                });
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    // This snapshot doesn't carry the `game`/`helpers` crates that define `Player`, `BoardState`,
+    // `OwnedGameView` and `Cards`, so these tests only rely on the fields/methods this file
+    // already uses on them (`player`, `board`, `other_hands`, `get_other_players`, `get_hand`,
+    // `get_board`) and otherwise treat them as opaque `Default` values.
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    #[should_panic(expected = "Modulus multiplication overflowed")]
+    fn debug_assert_no_overflow_mul_panics_near_u64_max() {
+        debug_assert_no_overflow_mul(u64::MAX, 2);
+    }
+
+    #[test]
+    fn debug_assert_no_overflow_mul_allows_non_overflowing_values_near_u64_max() {
+        debug_assert_no_overflow_mul(u64::MAX, 1);
+        debug_assert_no_overflow_mul(u64::MAX / 2, 2);
+    }
+
+    /// Drives the actual chained `combine`/`info_remaining` path (not just the bare `checked_mul`
+    /// helper) with a modulus large enough that `info_remaining`'s internal headroom check
+    /// (`self.modulus * (result + 1 - 1)`) overflows `u64` once `result` grows past what fits
+    /// alongside `self.modulus` - the scenario the chunk0-3 request was actually worried about.
+    #[test]
+    #[should_panic(expected = "Modulus multiplication overflowed")]
+    fn combine_panics_when_info_remaining_overflows_near_u64_max() {
+        let mut info = ModulusInformation { modulus: 1 << 40, value: 0 };
+        info.combine(ModulusInformation { modulus: 2, value: 0 }, u64::MAX);
+    }
+
+    /// The same chained `combine` path, but with a `max_modulus` that leaves enough headroom for
+    /// `self.modulus * other.modulus` (and `info_remaining`'s internal checks) to stay within
+    /// `u64`: the moduli themselves are small, while `max_modulus` (`1 << 62`) is far beyond
+    /// `u32::MAX`.
+    #[test]
+    fn combine_handles_large_moduli_without_overflow_when_they_fit() {
+        let mut info = ModulusInformation { modulus: 1_000, value: 0 };
+        info.combine(ModulusInformation { modulus: 1_000, value: 5 }, 1 << 62);
+        assert_eq!(info.value, 5_000);
+        assert_eq!(info.modulus, 1_000_000);
+    }
+
+    /// A `PublicInformation` that records, per player, every `Modulus` answer it encoded or decoded
+    /// for that player via a side channel shared across clones of the same player's state - this
+    /// lets `agrees_with` detect an encode/decode mismatch without needing to know how to
+    /// construct real `HandInfo<CardPossibilityTable>` content. `get_player_info`/`set_player_info`
+    /// are still real pass-throughs (backed by `hand_infos`) so `to_snapshot`/`from_snapshot`
+    /// round-trip a genuine per-player mapping rather than a no-op.
+    #[derive(Clone)]
+    struct FakeConvention {
+        markers: Rc<RefCell<Vec<(Player, Modulus)>>>,
+        hand_infos: Rc<RefCell<Vec<(Player, HandInfo<CardPossibilityTable>)>>>,
+    }
+
+    /// Always answers `1`, regardless of the hand it's asked about: in these tests, divergence
+    /// comes entirely from which players a move's `OwnedGameView`s say are present, not from hand
+    /// content.
+    struct FakeQuestion {
+        target: Player,
+        markers: Rc<RefCell<Vec<(Player, Modulus)>>>,
+    }
+
+    impl Question for FakeQuestion {
+        fn info_amount(&self) -> Modulus { 2 }
+        fn answer(&self, _hand: &Cards, _board: &BoardState) -> Modulus { 1 }
+        fn acknowledge_answer(&self, value: Modulus, _hand_info: &mut HandInfo<CardPossibilityTable>, _board: &BoardState) {
+            self.markers.borrow_mut().push((self.target.clone(), value));
+        }
+    }
+
+    impl PublicInformation for FakeConvention {
+        fn get_player_info(&self, player: &Player) -> HandInfo<CardPossibilityTable> {
+            self.hand_infos.borrow().iter()
+                .find(|(p, _)| p == player)
+                .map(|(_, info)| info.clone())
+                .unwrap_or_default()
+        }
+
+        fn set_player_info(&mut self, player: &Player, info: HandInfo<CardPossibilityTable>) {
+            let mut hand_infos = self.hand_infos.borrow_mut();
+            match hand_infos.iter_mut().find(|(p, _)| p == player) {
+                Some(entry) => entry.1 = info,
+                None => hand_infos.push((player.clone(), info)),
+            }
+        }
+
+        fn new(_board: &BoardState) -> Self {
+            FakeConvention {
+                markers: Rc::new(RefCell::new(Vec::new())),
+                hand_infos: Rc::new(RefCell::new(Vec::new())),
+            }
+        }
+
+        fn set_board(&mut self, _board: &BoardState) {
+        }
+
+        fn agrees_with(&self, other: Self) -> bool {
+            let ours = self.markers.borrow();
+            let theirs = other.markers.borrow();
+            ours.iter().all(|(player, value)| {
+                theirs.iter().all(|(other_player, other_value)| player != other_player || value == other_value)
+            })
+        }
+
+        fn ask_questions<Callback>(
+            &self, player: &Player, hand_info: &mut HandInfo<CardPossibilityTable>, mut callback: Callback, total_info: Modulus
+        )
+            where Callback: FnMut(&mut HandInfo<CardPossibilityTable>, &mut Modulus, Box<Question>)
+        {
+            let mut info_remaining = total_info;
+            let question = FakeQuestion { target: player.clone(), markers: self.markers.clone() };
+            callback(hand_info, &mut info_remaining, Box::new(question));
+        }
+    }
+
+    fn view_for(player: Player, board: BoardState, other_players: &[Player]) -> OwnedGameView {
+        OwnedGameView {
+            player: player,
+            board: board,
+            other_hands: other_players.iter().map(|p| (p.clone(), Cards::default())).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replay_detects_a_view_that_drops_a_player() {
+        let p0 = Player(0);
+        let p1 = Player(1);
+        let p2 = Player(2);
+        let players = vec![p0.clone(), p1.clone(), p2.clone()];
+        let board = BoardState { player: p0.clone(), ..Default::default() };
+
+        // P0 (the actor) and P1 both correctly see both other players.
+        let view0 = view_for(p0.clone(), board.clone(), &[p1.clone(), p2.clone()]);
+        let view1 = view_for(p1.clone(), board.clone(), &[p0.clone(), p2.clone()]);
+        // P2's view is buggy: it has dropped P1 entirely, so P2 will under-subtract when decoding.
+        let view2 = view_for(p2.clone(), board.clone(), &[p0.clone()]);
+
+        let moves = vec![RecordedHatMove {
+            actor: p0.clone(),
+            num_states: 2,
+            views: vec![
+                (p0.clone(), view0),
+                (p1.clone(), view1),
+                (p2.clone(), view2),
+            ],
+        }];
+
+        let divergence = replay_and_find_divergence::<FakeConvention>(&players, &board, &moves, None)
+            .expect("P2's dropped-player view should have produced a divergence");
+        assert_eq!(divergence.move_index, 0);
+        assert_eq!(divergence.player, p2);
+    }
+
+    #[test]
+    fn replay_agrees_when_every_view_is_consistent() {
+        let p0 = Player(0);
+        let p1 = Player(1);
+        let p2 = Player(2);
+        let players = vec![p0.clone(), p1.clone(), p2.clone()];
+        let board = BoardState { player: p0.clone(), ..Default::default() };
+
+        let view0 = view_for(p0.clone(), board.clone(), &[p1.clone(), p2.clone()]);
+        let view1 = view_for(p1.clone(), board.clone(), &[p0.clone(), p2.clone()]);
+        let view2 = view_for(p2.clone(), board.clone(), &[p0.clone(), p1.clone()]);
+
+        let moves = vec![RecordedHatMove {
+            actor: p0.clone(),
+            num_states: 2,
+            views: vec![
+                (p0.clone(), view0),
+                (p1.clone(), view1),
+                (p2.clone(), view2),
+            ],
+        }];
+
+        assert!(replay_and_find_divergence::<FakeConvention>(&players, &board, &moves, None).is_none());
+    }
+
+    #[test]
+    fn common_knowledge_rng_is_independent_of_viewer_ordering() {
+        let p0 = Player(0);
+        let p1 = Player(1);
+        let board = BoardState { player: p0.clone(), ..Default::default() };
+
+        let mut info = FakeConvention::new(&board);
+        info.set_player_info(&p0, Default::default());
+        info.set_player_info(&p1, Default::default());
+
+        // Same underlying state, but each viewer sees `get_other_players()` in a different order
+        // (p1's own view omits itself and lists p0, while p0's omits itself and lists p1) - the
+        // hash must agree regardless.
+        let view_from_p0 = view_for(p0.clone(), board.clone(), &[p1.clone()]);
+        let view_from_p1 = view_for(p1.clone(), board.clone(), &[p0.clone()]);
+
+        assert_eq!(
+            info.common_knowledge_rng(&view_from_p0),
+            info.common_knowledge_rng(&view_from_p1)
+        );
+    }
+
+    #[test]
+    fn to_snapshot_and_from_snapshot_round_trip_preserves_player_associations() {
+        let p0 = Player(0);
+        let p1 = Player(1);
+        let board = BoardState { player: p0.clone(), ..Default::default() };
+        let players = vec![p0.clone(), p1.clone()];
+
+        let mut original = FakeConvention::new(&board);
+        original.set_player_info(&p0, Default::default());
+        original.set_player_info(&p1, Default::default());
+
+        let snapshot = original.to_snapshot(&players);
+        let restored = FakeConvention::from_snapshot(&board, &snapshot);
+
+        let restored_players: Vec<Player> = restored.to_snapshot(&players).hand_infos
+            .into_iter().map(|(player, _)| player).collect();
+        assert_eq!(restored_players, players);
+    }
+
+    #[test]
+    fn replay_and_find_divergence_can_resume_from_a_starting_snapshot() {
+        let p0 = Player(0);
+        let p1 = Player(1);
+        let p2 = Player(2);
+        let players = vec![p0.clone(), p1.clone(), p2.clone()];
+        let board = BoardState { player: p0.clone(), ..Default::default() };
+
+        // Build a starting snapshot as if we'd already replayed some earlier moves, and check that
+        // a replay started from it (via `from_snapshot`) with consistent views finds no
+        // divergence.
+        let mut starting_state = FakeConvention::new(&board);
+        starting_state.set_player_info(&p0, Default::default());
+        let starting_snapshot = starting_state.to_snapshot(&players);
+
+        let view0 = view_for(p0.clone(), board.clone(), &[p1.clone(), p2.clone()]);
+        let view1 = view_for(p1.clone(), board.clone(), &[p0.clone(), p2.clone()]);
+        let view2 = view_for(p2.clone(), board.clone(), &[p0.clone(), p1.clone()]);
+
+        let moves = vec![RecordedHatMove {
+            actor: p0.clone(),
+            num_states: 2,
+            views: vec![
+                (p0.clone(), view0),
+                (p1.clone(), view1),
+                (p2.clone(), view2),
+            ],
+        }];
+
+        assert!(
+            replay_and_find_divergence::<FakeConvention>(&players, &board, &moves, Some(&starting_snapshot))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn entropy_of_uniform_distribution_is_log2_of_len() {
+        assert_eq!(entropy(&[0.25, 0.25, 0.25, 0.25]), 2.0);
+    }
+
+    #[test]
+    fn entropy_of_certain_distribution_is_zero() {
+        assert_eq!(entropy(&[1.0, 0.0, 0.0]), 0.0);
+    }
+
+    /// A question whose `answer_distribution` is fixed at construction time, so greedy selection
+    /// can be tested against a chosen distribution instead of whatever the default implies.
+    struct ScriptedQuestion {
+        info_amount: Modulus,
+        distribution: Vec<f32>,
+    }
+
+    impl Question for ScriptedQuestion {
+        fn info_amount(&self) -> Modulus { self.info_amount }
+        fn answer(&self, _hand: &Cards, _board: &BoardState) -> Modulus { 0 }
+        fn acknowledge_answer(&self, _value: Modulus, _hand_info: &mut HandInfo<CardPossibilityTable>, _board: &BoardState) {
+        }
+        fn answer_distribution(&self, _hand_info: &HandInfo<CardPossibilityTable>, _board: &BoardState) -> Vec<f32> {
+            self.distribution.clone()
+        }
+    }
+
+    /// A question that relies entirely on `Question`'s default `answer_distribution`.
+    struct PlainQuestion {
+        info_amount: Modulus,
+    }
+
+    impl Question for PlainQuestion {
+        fn info_amount(&self) -> Modulus { self.info_amount }
+        fn answer(&self, _hand: &Cards, _board: &BoardState) -> Modulus { 0 }
+        fn acknowledge_answer(&self, _value: Modulus, _hand_info: &mut HandInfo<CardPossibilityTable>, _board: &BoardState) {
+        }
+    }
+
+    #[test]
+    fn default_answer_distribution_is_uniform() {
+        let question = PlainQuestion { info_amount: 4 };
+        let hand_info = Default::default();
+        let board = Default::default();
+        assert_eq!(
+            question.answer_distribution(&hand_info, &board),
+            vec![0.25, 0.25, 0.25, 0.25]
+        );
+    }
+
+    #[test]
+    fn select_greedy_question_prefers_the_more_informative_candidate() {
+        let hand_info = Default::default();
+        let board = Default::default();
+        let candidates: Vec<Box<Question>> = vec![
+            Box::new(ScriptedQuestion { info_amount: 4, distribution: vec![0.97, 0.01, 0.01, 0.01] }),
+            Box::new(ScriptedQuestion { info_amount: 4, distribution: vec![0.25, 0.25, 0.25, 0.25] }),
+        ];
+        let chosen = select_greedy_question(candidates, &hand_info, &board, 100).unwrap();
+        assert_eq!(chosen.info_amount(), 4);
+        assert_eq!(
+            chosen.answer_distribution(&hand_info, &board),
+            vec![0.25, 0.25, 0.25, 0.25]
+        );
+    }
+
+    #[test]
+    fn select_greedy_question_filters_out_candidates_that_dont_fit_the_budget() {
+        let hand_info = Default::default();
+        let board = Default::default();
+        let candidates: Vec<Box<Question>> = vec![
+            Box::new(ScriptedQuestion { info_amount: 8, distribution: vec![0.125; 8] }),
+            Box::new(ScriptedQuestion { info_amount: 4, distribution: vec![0.25; 4] }),
+        ];
+        let chosen = select_greedy_question(candidates, &hand_info, &board, 4).unwrap();
+        assert_eq!(chosen.info_amount(), 4);
+    }
+
+    #[test]
+    fn select_greedy_question_returns_none_when_nothing_fits() {
+        let hand_info = Default::default();
+        let board = Default::default();
+        let candidates: Vec<Box<Question>> = vec![
+            Box::new(ScriptedQuestion { info_amount: 8, distribution: vec![0.125; 8] }),
+        ];
+        assert!(select_greedy_question(candidates, &hand_info, &board, 4).is_none());
+    }
+
+    #[test]
+    fn select_greedy_question_does_not_panic_on_a_degenerate_distribution() {
+        let hand_info = Default::default();
+        let board = Default::default();
+        let candidates: Vec<Box<Question>> = vec![
+            Box::new(ScriptedQuestion { info_amount: 4, distribution: vec![f32::NAN; 4] }),
+            Box::new(ScriptedQuestion { info_amount: 4, distribution: vec![0.25; 4] }),
+        ];
+        select_greedy_question(candidates, &hand_info, &board, 100);
+    }
+}